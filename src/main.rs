@@ -1,4 +1,4 @@
-use clap::Parser;
+use clap::{CommandFactory, FromArgMatches, Parser};
 use log::info;
 use ring::digest::{Context, SHA256};
 use serde::{Deserialize, Serialize};
@@ -9,17 +9,25 @@ use std::{
     collections::HashSet,
     fs::File,
     io::{Read, Write},
-    path::Path,
-    sync::{Arc, Mutex},
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex,
+    },
 };
 use tar::Header;
 
 #[derive(Parser)]
 struct Cli {
-    /// The compressoin type, either zstd or gzip, required
+    /// The compressoin type, either zstd, gzip or none, required
     #[clap(short, long)]
     compression: String,
 
+    /// zstd compression level, only used when --compression zstd. Higher
+    /// trades encode speed for a better ratio.
+    #[clap(long, default_value_t = 3)]
+    zstd_level: i32,
+
     /// Compute hash
     #[clap(long, default_value = "false")]
     hash: bool,
@@ -40,12 +48,139 @@ struct Cli {
 
     #[clap(long)]
     tar_source_from: Option<String>,
+
+    /// Maximum cumulative apparent (sparse-aware) size, in bytes, that a single
+    /// `unpack_from` run is allowed to materialize across all entries.
+    #[clap(long, default_value_t = 1_u64 << 40)]
+    max_apparent_size: u64,
+
+    /// Maximum cumulative on-disk size, in bytes, that a single `unpack_from`
+    /// run is allowed to write (fallocate'd space and bytes copied).
+    #[clap(long, default_value_t = 1_u64 << 40)]
+    max_disk_size: u64,
+
+    /// Maximum number of entries (files, directories, links, metadata
+    /// records) a single `unpack_from` run is allowed to process.
+    #[clap(long, default_value_t = 10_000_000)]
+    max_entry_count: u64,
+
+    /// Abort the whole unpack run on the first sha256 sidecar mismatch.
+    /// Without it, a split that fails verification is reported, its dedup
+    /// references are dropped, and the run goes on with the other splits.
+    /// Sidecars are checked while a split is extracted, so in either mode the
+    /// failing split's entries have already been written when the mismatch
+    /// is found.
+    #[clap(long, default_value_t = false)]
+    fail_fast: bool,
+
+    /// Only pack/unpack entries matching this glob, relative to the archive
+    /// root. Repeatable; combined with --exclude into one ordered list
+    /// (command-line order) where the last matching pattern wins. To prune a
+    /// whole directory, give a pattern that also covers its contents, e.g.
+    /// `some-dir/**`.
+    #[clap(long)]
+    include: Vec<String>,
+
+    /// Skip entries matching this glob. See --include.
+    #[clap(long)]
+    exclude: Vec<String>,
+
+    /// Whether an entry matching neither --include nor --exclude is kept.
+    /// Defaults to keeping everything; pass `--match-default-include false`
+    /// to require an explicit --include instead.
+    #[clap(long, default_value_t = true, action = clap::ArgAction::Set)]
+    match_default_include: bool,
+}
+
+/// One `--include`/`--exclude` rule: a compiled glob plus whether a match
+/// means "keep" or "skip".
+struct MatchRule {
+    pattern: glob::Pattern,
+    include: bool,
+}
+
+/// An ordered include/exclude glob rule list, consulted by both
+/// `WriterState::write` (pack) and `unpack_one_tar` (unpack) so a subset of
+/// an archive can be packed or extracted. Rules are kept in command-line
+/// order (interleaving `--include` and `--exclude` as given) and the last
+/// rule that matches a path decides whether it's kept; if nothing matches,
+/// `default_include` decides.
+struct MatchList {
+    rules: Vec<MatchRule>,
+    default_include: bool,
+}
+
+impl MatchList {
+    /// Builds the rule list from raw `ArgMatches` rather than the parsed
+    /// `Cli` struct: `Cli::include`/`Cli::exclude` are each just a `Vec<String>`
+    /// and lose the relative order the two flags were given on the command
+    /// line, which is exactly the order "last match wins" needs.
+    fn new(matches: &clap::ArgMatches, default_include: bool) -> Self {
+        let mut indexed: Vec<(usize, MatchRule)> = Vec::new();
+        if let Some(indices) = matches.indices_of("include") {
+            let values = matches.get_many::<String>("include").unwrap();
+            for (index, pattern) in indices.zip(values) {
+                indexed.push((
+                    index,
+                    MatchRule {
+                        pattern: glob::Pattern::new(pattern).unwrap(),
+                        include: true,
+                    },
+                ));
+            }
+        }
+        if let Some(indices) = matches.indices_of("exclude") {
+            let values = matches.get_many::<String>("exclude").unwrap();
+            for (index, pattern) in indices.zip(values) {
+                indexed.push((
+                    index,
+                    MatchRule {
+                        pattern: glob::Pattern::new(pattern).unwrap(),
+                        include: false,
+                    },
+                ));
+            }
+        }
+        indexed.sort_by_key(|(index, _)| *index);
+
+        Self {
+            rules: indexed.into_iter().map(|(_, rule)| rule).collect(),
+            default_include,
+        }
+    }
+
+    /// Whether `path` should be kept: the last rule that matches it wins,
+    /// falling back to `default_include` if nothing matches.
+    fn matches(&self, path: &str) -> bool {
+        self.rules
+            .iter()
+            .rev()
+            .find(|rule| rule.pattern.matches(path))
+            .map(|rule| rule.include)
+            .unwrap_or(self.default_include)
+    }
+
+    /// Appends unconditional "include" rules for `paths`, which since the
+    /// last matching rule wins, override any `--exclude` that would
+    /// otherwise have dropped them. Used to pull a dedup "original" back in
+    /// when a still-included duplicate's reference depends on it.
+    fn with_forced_includes(mut self, paths: &HashSet<String>) -> Self {
+        for path in paths {
+            self.rules.push(MatchRule {
+                pattern: glob::Pattern::new(&glob::Pattern::escape(path)).unwrap(),
+                include: true,
+            });
+        }
+        self
+    }
 }
 
 struct WriterState {
     split_size: u64,
     split_to: String,
     compression: String,
+    zstd_level: i32,
+    match_list: MatchList,
 
     current_split_file: Option<tar::Builder<Box<dyn Write>>>,
     current_split_file_size: u64,
@@ -55,6 +190,11 @@ struct WriterState {
     split_file_name: String,
 
     tar_source_from: Option<String>,
+
+    /// Cross-shard dedup index: file size -> (partial hash, full hash, path)
+    /// of every regular file stored in full so far across all splits. Files
+    /// large enough to be split across archives are never indexed.
+    dedup_index: std::collections::HashMap<u64, Vec<(String, String, String)>>,
 }
 
 #[derive(Debug, Serialize, Deserialize, Clone)]
@@ -65,6 +205,19 @@ struct SplitMetadata {
     total_size: u64,
 }
 
+/// Written in place of a regular file's data when `WriterState::write` finds
+/// a byte-identical file already written earlier in the run (possibly in a
+/// different split). `unpack_one_tar` collects these instead of applying
+/// them; once every split's regular files have been extracted,
+/// `unpack_split_tars` hard_links/copies `points_to` onto `path` via
+/// `apply_dedup_reference`.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+struct DedupReference {
+    path: String,
+    points_to: String,
+    total_size: u64,
+}
+
 struct PassThroughHashWriter<T: std::io::Write> {
     hash_context: Context,
     inner: T,
@@ -90,8 +243,12 @@ impl<T: std::io::Write> Drop for PassThroughHashWriter<T> {
 
 impl<T: std::io::Write> std::io::Write for PassThroughHashWriter<T> {
     fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
-        self.hash_context.update(buf);
-        self.inner.write(buf)
+        // Only hash the bytes `inner` actually accepted: on a short write the
+        // caller (e.g. `write_all`) retries with the remainder, so hashing
+        // the full `buf` here would double-count the unwritten tail.
+        let n = self.inner.write(buf)?;
+        self.hash_context.update(&buf[..n]);
+        Ok(n)
     }
 
     fn flush(&mut self) -> std::io::Result<()> {
@@ -99,12 +256,178 @@ impl<T: std::io::Write> std::io::Write for PassThroughHashWriter<T> {
     }
 }
 
+/// Read-side counterpart to `PassThroughHashWriter`: hashes every byte that
+/// passes through on the way out of `inner`, and on drop records a mismatch
+/// (rather than panicking, since a reader is usually consumed deep inside a
+/// `tar::Archive` where we can't return a `Result` from `drop`). The caller
+/// checks `mismatch` once the archive has been fully read.
+struct HashingReader<T: std::io::Read> {
+    hash_context: Context,
+    inner: T,
+    expected_hex: String,
+    label: String,
+    mismatch: Arc<Mutex<Option<String>>>,
+}
+
+impl<T: std::io::Read> HashingReader<T> {
+    fn new(
+        inner: T,
+        expected_hex: String,
+        label: String,
+        mismatch: Arc<Mutex<Option<String>>>,
+    ) -> Self {
+        Self {
+            hash_context: Context::new(&SHA256),
+            inner,
+            expected_hex,
+            label,
+            mismatch,
+        }
+    }
+}
+
+impl<T: std::io::Read> std::io::Read for HashingReader<T> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let n = self.inner.read(buf)?;
+        self.hash_context.update(&buf[..n]);
+        Ok(n)
+    }
+}
+
+impl<T: std::io::Read> Drop for HashingReader<T> {
+    fn drop(&mut self) {
+        let actual = data_encoding::HEXLOWER.encode(self.hash_context.clone().finish().as_ref());
+        if actual != self.expected_hex {
+            *self.mismatch.lock().unwrap() = Some(format!(
+                "{} sha256 mismatch: expected {}, got {}",
+                self.label, self.expected_hex, actual
+            ));
+        }
+    }
+}
+
+/// Reads and trims a `*.sha256` sidecar next to `split_path`, if present.
+fn read_sidecar_hash(split_path: &Path, suffix: &str) -> Option<String> {
+    let sidecar = format!("{}.{}.sha256", split_path.display(), suffix);
+    std::fs::read_to_string(sidecar)
+        .ok()
+        .map(|s| s.trim().to_string())
+}
+
+const ZSTD_MAGIC: [u8; 4] = [0x28, 0xB5, 0x2F, 0xFD];
+const GZIP_MAGIC: [u8; 2] = [0x1F, 0x8B];
+
+/// Peeks at the first few bytes of a split to detect which codec the packer
+/// used (the unpacker doesn't otherwise know, since splits can be produced
+/// with different `--compression` settings over time) and wraps `reader`
+/// with the matching decoder.
+fn wrap_decoder(mut reader: Box<dyn Read>) -> std::io::Result<Box<dyn Read>> {
+    let mut magic = [0u8; 4];
+    let mut filled = 0;
+    while filled < magic.len() {
+        let n = reader.read(&mut magic[filled..])?;
+        if n == 0 {
+            break;
+        }
+        filled += n;
+    }
+    let prefixed: Box<dyn Read> =
+        Box::new(std::io::Cursor::new(magic[..filled].to_vec()).chain(reader));
+
+    if filled == 4 && magic == ZSTD_MAGIC {
+        Ok(Box::new(zstd::stream::read::Decoder::new(prefixed)?))
+    } else if filled >= 2 && magic[..2] == GZIP_MAGIC {
+        Ok(Box::new(flate2::read::GzDecoder::new(prefixed)))
+    } else {
+        Ok(prefixed)
+    }
+}
+
+/// Builds one POSIX PAX extended-header record: `"<len> <key>=<value>\n"`,
+/// where `<len>` counts the whole record including its own digits. Adding
+/// the length can push the digit count over a power of ten, so iterate to a
+/// fixed point rather than computing it in one shot.
+fn pax_extension_record(key: &str, value: &str) -> Vec<u8> {
+    let mut len = key.len() + value.len() + 3;
+    loop {
+        let next = len.to_string().len() + key.len() + value.len() + 3;
+        if next == len {
+            break;
+        }
+        len = next;
+    }
+    format!("{} {}={}\n", len, key, value).into_bytes()
+}
+
+/// Truncates `path` to fit the ustar header's 100-byte name field, on a
+/// UTF-8 boundary. Only meaningful as a placeholder: once a PAX "path" (or
+/// "linkpath") record is written, readers ignore the paired entry's own
+/// name/linkname in favor of the PAX value.
+fn truncated_placeholder_name(path: &str) -> String {
+    const USTAR_NAME_MAX: usize = 100;
+    let mut end = path.len().min(USTAR_NAME_MAX);
+    while end > 0 && !path.is_char_boundary(end) {
+        end -= 1;
+    }
+    path[..end].to_string()
+}
+
+/// Appends `data` as a tar entry named `path` (with link target `link_name`
+/// for symlinks/hardlinks), emitting a PAX extended header (`XHeader`) first
+/// for whichever of `path`/`link_name` doesn't fit the ustar header's
+/// 100-byte field. This is what lets entries with arbitrarily long paths
+/// survive the split/reassemble round trip: `unpack_one_tar` needs no
+/// special handling, since `tar::Archive` already merges a preceding PAX
+/// header's records into the entry it describes before handing it out.
+fn append_with_pax<R: Read>(
+    builder: &mut tar::Builder<Box<dyn Write>>,
+    header: &mut Header,
+    path: &str,
+    link_name: Option<&str>,
+    data: R,
+) -> std::io::Result<()> {
+    let mut pax_body = Vec::new();
+    if header.set_path(path).is_err() {
+        pax_body.extend(pax_extension_record("path", path));
+    }
+    if let Some(link_name) = link_name {
+        if header.set_link_name(link_name).is_err() {
+            pax_body.extend(pax_extension_record("linkpath", link_name));
+        }
+    }
+
+    if !pax_body.is_empty() {
+        let mut pax_header = Header::new_ustar();
+        pax_header.set_entry_type(tar::EntryType::XHeader);
+        pax_header.set_mode(0o644);
+        pax_header.set_size(pax_body.len() as u64);
+        pax_header
+            .set_path(format!("PaxHeaders.0/{}", truncated_placeholder_name(path)))
+            .or_else(|_| pax_header.set_path("PaxHeaders.0/long-name"))?;
+        pax_header.set_cksum();
+        builder.append(&pax_header, pax_body.as_slice())?;
+
+        // The placeholder name/linkname below is only ever read as a
+        // fallback by tools that don't understand PAX; real values come
+        // from the extended header written above.
+        header.set_path(truncated_placeholder_name(path))?;
+        if let Some(link_name) = link_name {
+            header.set_link_name(truncated_placeholder_name(link_name))?;
+        }
+    }
+
+    header.set_cksum();
+    builder.append(header, data)
+}
+
 impl WriterState {
     fn new(
         split_size: u64,
         split_to: String,
         compression: String,
+        zstd_level: i32,
         tar_source_from: Option<String>,
+        match_list: MatchList,
     ) -> Self {
         Self {
             current_split_file: None,
@@ -115,7 +438,10 @@ impl WriterState {
             split_size,
             split_to,
             compression,
+            zstd_level,
             tar_source_from,
+            match_list,
+            dedup_index: std::collections::HashMap::new(),
         }
     }
 
@@ -134,26 +460,27 @@ impl WriterState {
             self.split_file_name =
                 format!("{}.{:03}", self.split_to, self.num_split_files_completed);
             let split_file = std::fs::File::create(&self.split_file_name).unwrap();
-            // let split_file: Box<dyn Write> = match self.compression.as_str() {
-            //     "zstd" => Box::new(zstd::stream::write::Encoder::new(split_file, 3).unwrap()),
-            //     "gzip" => Box::new(flate2::write::GzEncoder::new(
-            //         split_file,
-            //         flate2::Compression::fast(),
-            //     )),
-            //     "none" => Box::new(split_file),
-            //     _ => {
-            //         panic!("Unknown compression type, must be either zstd or gzip");
-            //     }
-            // };
             let hash_writer = PassThroughHashWriter::new(
                 split_file,
                 format!("{}.compressed.sha256", self.split_file_name),
             );
-            let encoder = zstd::stream::write::Encoder::new(hash_writer, 3)
-                .unwrap()
-                .auto_finish();
+            let compressed_writer: Box<dyn Write> = match self.compression.as_str() {
+                "zstd" => Box::new(
+                    zstd::stream::write::Encoder::new(hash_writer, self.zstd_level)
+                        .unwrap()
+                        .auto_finish(),
+                ),
+                "gzip" => Box::new(flate2::write::GzEncoder::new(
+                    hash_writer,
+                    flate2::Compression::fast(),
+                )),
+                "none" => Box::new(hash_writer),
+                _ => {
+                    panic!("Unknown compression type, must be one of zstd, gzip, none");
+                }
+            };
             let writer = PassThroughHashWriter::new(
-                encoder,
+                compressed_writer,
                 format!("{}.uncompressed.sha256", self.split_file_name),
             );
             self.current_split_file = Some(tar::Builder::new(Box::new(writer)));
@@ -161,6 +488,12 @@ impl WriterState {
     }
 
     fn write(&mut self, mut entry: tar::Entry<'_, Box<dyn Read>>) {
+        let path = entry.path().unwrap().display().to_string();
+        if !self.match_list.matches(&path) {
+            info!("Skipping {} (excluded by --include/--exclude)", &path);
+            return;
+        }
+
         let mut file_size = entry.header().size().unwrap().clone();
         // https://github.com/alexcrichton/tar-rs/issues/286
         if let Some(mut pax) = entry.pax_extensions().unwrap() {
@@ -203,18 +536,26 @@ impl WriterState {
         }
         self.ensure_new_file();
 
-        let path = entry.path().unwrap().display().to_string();
-
         // Check links
         {
             if entry.header().entry_type().is_hard_link() {
                 let target_path = entry.link_name().unwrap().unwrap().display().to_string();
-                assert!(
-                self.current_file_contains_path.contains(&target_path),
-                "Current file {} is a hard link to {}, but the target file isn't in this archive. This will cause trouble during extraction",
-                &path,
-                &target_path
-            );
+                if !self.current_file_contains_path.contains(&target_path) {
+                    if !self.match_list.matches(&target_path) {
+                        // The target was itself dropped by --include/--exclude:
+                        // drop this hard link along with it, rather than
+                        // emitting a link to content that was never packed.
+                        info!(
+                            "Skipping {} (hard link target {} was excluded by --include/--exclude)",
+                            &path, &target_path
+                        );
+                        return;
+                    }
+                    panic!(
+                        "Current file {} is a hard link to {}, but the target file isn't in this archive. This will cause trouble during extraction",
+                        &path, &target_path
+                    );
+                }
             }
             self.current_file_contains_path.insert(path.clone());
         }
@@ -254,15 +595,15 @@ impl WriterState {
 
                     let mut metadata_header = Header::new_gnu();
                     metadata_header.set_size(metadata_json_bytes.len() as u64);
-                    metadata_header.set_cksum();
-
-                    current_split_file
-                        .append_data(
-                            &mut metadata_header,
-                            format!("{}.split-metadata.{}.json", path, segment_idx),
-                            metadata_json_bytes,
-                        )
-                        .unwrap();
+
+                    append_with_pax(
+                        current_split_file,
+                        &mut metadata_header,
+                        &format!("{}.split-metadata.{}.json", path, segment_idx),
+                        None,
+                        metadata_json_bytes,
+                    )
+                    .unwrap();
                 }
 
                 // Write the actual data
@@ -271,14 +612,17 @@ impl WriterState {
                     let old_header = entry.header().clone();
                     chunk_header.set_size(chunk_size);
                     chunk_header.set_entry_type(tar::EntryType::Regular);
-                    chunk_header.set_path(&path).unwrap();
                     chunk_header.set_uid(old_header.uid().unwrap());
                     chunk_header.set_gid(old_header.gid().unwrap());
-                    chunk_header.set_cksum();
                     let mut chunk_data = entry.take(chunk_size as u64);
-                    current_split_file
-                        .append(&chunk_header, &mut chunk_data)
-                        .unwrap();
+                    append_with_pax(
+                        current_split_file,
+                        &mut chunk_header,
+                        &path,
+                        None,
+                        &mut chunk_data,
+                    )
+                    .unwrap();
                     entry = chunk_data.into_inner();
                 }
 
@@ -293,6 +637,62 @@ impl WriterState {
                 self.current_split_file_size += chunk_size;
                 self.current_file_contains_path.insert(path.clone());
             }
+        } else if entry.header().entry_type().is_file() && file_size > 0 {
+            // Small enough to fit in one split, and a real file (not a
+            // directory/symlink/hardlink) - eligible for dedup. We need the
+            // full content before deciding what to write, so buffer it; this
+            // is bounded by split_size since oversized files already took
+            // the streaming branch above.
+            let header = entry.header().clone();
+            let mut buf = Vec::with_capacity(file_size as usize);
+            entry.read_to_end(&mut buf).unwrap();
+
+            match self.dedup_lookup_or_insert(file_size, &buf, path.clone()) {
+                Some(points_to) => {
+                    info!(
+                        "Deduplicating {} (size {}) against {}",
+                        &path, file_size, &points_to
+                    );
+                    let dedup_reference = DedupReference {
+                        path: path.clone(),
+                        points_to,
+                        total_size: file_size,
+                    };
+                    let reference_json = serde_json::to_string(&dedup_reference).unwrap();
+                    let reference_json_bytes = reference_json.as_bytes();
+
+                    let mut reference_header = Header::new_gnu();
+                    reference_header.set_size(reference_json_bytes.len() as u64);
+
+                    let current_split_file = self.current_split_file.as_mut().unwrap();
+                    append_with_pax(
+                        current_split_file,
+                        &mut reference_header,
+                        &format!("{}.dedup-reference.json", path),
+                        None,
+                        reference_json_bytes,
+                    )
+                    .unwrap();
+                }
+                None => {
+                    if file_size > 1000000 {
+                        info!(
+                            "Writing {} (size {}) to {}",
+                            &path, &file_size, self.split_file_name
+                        );
+                    }
+                    let current_split_file = self.current_split_file.as_mut().unwrap();
+                    append_with_pax(
+                        current_split_file,
+                        &mut header.clone(),
+                        &path,
+                        None,
+                        buf.as_slice(),
+                    )
+                    .unwrap();
+                    self.current_split_file_size += file_size;
+                }
+            }
         } else {
             if file_size > 1000000 {
                 info!(
@@ -300,13 +700,70 @@ impl WriterState {
                     &path, &file_size, self.split_file_name
                 );
             }
+            let entry_type = entry.header().entry_type();
+            let link_name = if entry_type.is_symlink() || entry_type.is_hard_link() {
+                entry
+                    .link_name()
+                    .unwrap()
+                    .map(|target| target.display().to_string())
+            } else {
+                None
+            };
             let current_split_file = self.current_split_file.as_mut().unwrap();
-            current_split_file
-                .append_data(&mut entry.header().clone(), path, entry)
-                .unwrap();
+            append_with_pax(
+                current_split_file,
+                &mut entry.header().clone(),
+                &path,
+                link_name.as_deref(),
+                entry,
+            )
+            .unwrap();
             self.current_split_file_size += file_size;
         }
     }
+
+    /// Two-stage duplicate check: same size, then a cheap partial hash of
+    /// the first/last 4 KiB to build a candidate set, then a full SHA256 to
+    /// confirm. Returns the path of an existing identical file if found,
+    /// otherwise records `path`'s hashes for future comparisons and returns
+    /// `None`.
+    fn dedup_lookup_or_insert(&mut self, size: u64, buf: &[u8], path: String) -> Option<String> {
+        let partial_hash = Self::partial_hash(buf);
+        let has_candidates = self
+            .dedup_index
+            .get(&size)
+            .is_some_and(|candidates| candidates.iter().any(|(p, _, _)| *p == partial_hash));
+
+        let full_hash = Self::full_hash(buf);
+        if has_candidates {
+            if let Some((_, _, existing_path)) = self.dedup_index[&size]
+                .iter()
+                .find(|(p, f, _)| *p == partial_hash && *f == full_hash)
+            {
+                return Some(existing_path.clone());
+            }
+        }
+        self.dedup_index
+            .entry(size)
+            .or_default()
+            .push((partial_hash, full_hash, path));
+        None
+    }
+
+    fn partial_hash(buf: &[u8]) -> String {
+        let mut ctx = Context::new(&SHA256);
+        let head_len = buf.len().min(4096);
+        ctx.update(&buf[..head_len]);
+        let tail_len = buf.len().min(4096);
+        ctx.update(&buf[buf.len() - tail_len..]);
+        data_encoding::HEXLOWER.encode(ctx.finish().as_ref())
+    }
+
+    fn full_hash(buf: &[u8]) -> String {
+        let mut ctx = Context::new(&SHA256);
+        ctx.update(buf);
+        data_encoding::HEXLOWER.encode(ctx.finish().as_ref())
+    }
 }
 
 fn ensure_parent_dir_exists(path: &std::path::PathBuf) {
@@ -315,36 +772,272 @@ fn ensure_parent_dir_exists(path: &std::path::PathBuf) {
     std::fs::create_dir_all(&dir_path).unwrap();
 }
 
-fn unpack_one_tar(path: std::path::PathBuf, unpack_to: String, fallocate_lock: Arc<Mutex<()>>) {
-    let mut tar = tar::Archive::new(File::open(&path).unwrap());
+#[derive(Debug)]
+enum UnpackError {
+    PathTraversal(String),
+    LinkEscapesRoot { path: String, target: String },
+    ApparentSizeExceeded { limit: u64 },
+    DiskSizeExceeded { limit: u64 },
+    EntryCountExceeded { limit: u64 },
+    HashMismatch(String),
+    DedupTargetMissing { path: String, target: String },
+    HardLinkTargetMissing { path: String, target: String },
+}
+
+impl std::fmt::Display for UnpackError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            UnpackError::PathTraversal(path) => {
+                write!(f, "entry path {:?} escapes the unpack destination", path)
+            }
+            UnpackError::LinkEscapesRoot { path, target } => write!(
+                f,
+                "link {:?} -> {:?} would resolve outside the unpack destination",
+                path, target
+            ),
+            UnpackError::ApparentSizeExceeded { limit } => write!(
+                f,
+                "cumulative apparent size exceeded the configured limit of {} bytes",
+                limit
+            ),
+            UnpackError::DiskSizeExceeded { limit } => write!(
+                f,
+                "cumulative on-disk size exceeded the configured limit of {} bytes",
+                limit
+            ),
+            UnpackError::EntryCountExceeded { limit } => write!(
+                f,
+                "entry count exceeded the configured limit of {} entries",
+                limit
+            ),
+            UnpackError::HashMismatch(msg) => write!(f, "{}", msg),
+            UnpackError::DedupTargetMissing { path, target } => write!(
+                f,
+                "dedup reference {:?} points at {:?}, which was never extracted",
+                path, target
+            ),
+            UnpackError::HardLinkTargetMissing { path, target } => write!(
+                f,
+                "hard link {:?} -> {:?} points at a target that wasn't extracted yet",
+                path, target
+            ),
+        }
+    }
+}
+
+impl std::error::Error for UnpackError {}
+
+/// Configurable ceilings enforced while unpacking, to bound the damage a
+/// hostile or corrupt archive can do (archive bombs, runaway entry counts).
+struct UnpackLimits {
+    max_apparent_size: u64,
+    max_disk_size: u64,
+    max_entry_count: u64,
+}
+
+/// Cumulative counters shared across every split tar in a single
+/// `unpack_from` run, so the limits in `UnpackLimits` bound the whole run
+/// rather than just one split file.
+#[derive(Default)]
+struct UnpackCounters {
+    apparent_size: AtomicU64,
+    disk_size: AtomicU64,
+    entry_count: AtomicU64,
+}
+
+impl UnpackCounters {
+    fn account_entry(&self, limits: &UnpackLimits) -> Result<(), UnpackError> {
+        if self.entry_count.fetch_add(1, Ordering::Relaxed) + 1 > limits.max_entry_count {
+            return Err(UnpackError::EntryCountExceeded {
+                limit: limits.max_entry_count,
+            });
+        }
+        Ok(())
+    }
+
+    fn account_size(
+        &self,
+        limits: &UnpackLimits,
+        apparent: u64,
+        disk: u64,
+    ) -> Result<(), UnpackError> {
+        if self.apparent_size.fetch_add(apparent, Ordering::Relaxed) + apparent
+            > limits.max_apparent_size
+        {
+            return Err(UnpackError::ApparentSizeExceeded {
+                limit: limits.max_apparent_size,
+            });
+        }
+        if self.disk_size.fetch_add(disk, Ordering::Relaxed) + disk > limits.max_disk_size {
+            return Err(UnpackError::DiskSizeExceeded {
+                limit: limits.max_disk_size,
+            });
+        }
+        Ok(())
+    }
+}
+
+/// Rejects an archive-relative path unless every component is a plain
+/// (`Normal`) path segment or `.` (which is dropped). This is the same shape of check hardened tar
+/// unpackers use to reject absolute paths (`RootDir`/`Prefix`) and directory
+/// traversal (`ParentDir`) before a single byte is written to disk.
+fn sanitize_relative_path(raw: &str) -> Result<PathBuf, UnpackError> {
+    let mut sanitized = PathBuf::new();
+    for component in Path::new(raw).components() {
+        match component {
+            std::path::Component::Normal(part) => sanitized.push(part),
+            std::path::Component::CurDir => {}
+            _ => return Err(UnpackError::PathTraversal(raw.to_string())),
+        }
+    }
+    Ok(sanitized)
+}
+
+/// Lexically resolves `raw_target` starting from `base` (already known to be
+/// under `root`), the way a symlink/hardlink target is resolved: each
+/// `ParentDir` (`..`) component pops the last pushed component, refusing to
+/// pop past `root` itself (that would escape the destination); each
+/// `Normal` component pushes; an absolute target (`RootDir`) is rebased at
+/// `root` instead of being followed onto the host filesystem. Returns `None`
+/// if a `..` would climb above `root`.
+fn resolve_lexical(root: &Path, base: PathBuf, raw_target: &str) -> Option<PathBuf> {
+    let mut resolved = base;
+    for component in Path::new(raw_target).components() {
+        match component {
+            std::path::Component::Normal(part) => resolved.push(part),
+            std::path::Component::CurDir => {}
+            std::path::Component::ParentDir => {
+                if resolved == root || !resolved.pop() {
+                    return None;
+                }
+            }
+            std::path::Component::RootDir | std::path::Component::Prefix(_) => {
+                resolved = root.to_path_buf();
+            }
+        }
+    }
+    Some(resolved)
+}
+
+/// Resolves a symlink/hardlink target the way extraction needs to: absolute
+/// targets (e.g. `/etc/localtime -> /usr/share/zoneinfo/Etc/UTC`, common in
+/// container image layers) are rebased under `unpack_to_canon` rather than
+/// rejected, and relative `..` targets (e.g. `bin/sh -> ../bin/dash`) are
+/// resolved against the link's own parent directory. Only a target that
+/// still climbs above `unpack_to_canon` after that resolution is rejected as
+/// path traversal.
+fn resolve_link_target(
+    unpack_to_canon: &Path,
+    sanitized_link_path: &Path,
+    raw_target: &str,
+    link_path: &str,
+) -> Result<PathBuf, UnpackError> {
+    let parent = sanitized_link_path.parent().unwrap_or(Path::new(""));
+    let base = unpack_to_canon.join(parent);
+
+    let resolved = resolve_lexical(unpack_to_canon, base, raw_target).ok_or_else(|| {
+        UnpackError::LinkEscapesRoot {
+            path: link_path.to_string(),
+            target: raw_target.to_string(),
+        }
+    })?;
+
+    if !resolved.starts_with(unpack_to_canon) {
+        return Err(UnpackError::LinkEscapesRoot {
+            path: link_path.to_string(),
+            target: raw_target.to_string(),
+        });
+    }
+    Ok(resolved)
+}
+
+fn unpack_one_tar(
+    path: std::path::PathBuf,
+    unpack_to: String,
+    fallocate_lock: Arc<Mutex<()>>,
+    limits: &UnpackLimits,
+    counters: &UnpackCounters,
+    match_list: &MatchList,
+) -> Result<Vec<DedupReference>, UnpackError> {
+    std::fs::create_dir_all(&unpack_to).unwrap();
+    let unpack_to_canon = Path::new(&unpack_to).canonicalize().unwrap();
+
+    let mismatch: Arc<Mutex<Option<String>>> = Arc::new(Mutex::new(None));
+    let raw_file = File::open(&path).unwrap();
+    let reader: Box<dyn Read> = match read_sidecar_hash(&path, "compressed") {
+        Some(expected) => Box::new(HashingReader::new(
+            raw_file,
+            expected,
+            format!("{} (compressed)", path.display()),
+            mismatch.clone(),
+        )),
+        None => Box::new(raw_file),
+    };
+    let reader = wrap_decoder(reader).unwrap();
+    let reader: Box<dyn Read> = match read_sidecar_hash(&path, "uncompressed") {
+        Some(expected) => Box::new(HashingReader::new(
+            reader,
+            expected,
+            format!("{} (uncompressed)", path.display()),
+            mismatch.clone(),
+        )),
+        None => reader,
+    };
+
+    let mut tar = tar::Archive::new(reader);
     let mut maybe_split_metadata: Option<SplitMetadata> = None;
+    // Dedup references may point at an original living in a different split,
+    // which isn't necessarily extracted yet - defer linking them until every
+    // split in the run has extracted its regular files.
+    let mut dedup_references: Vec<DedupReference> = Vec::new();
     for entry in tar.entries().unwrap() {
         let mut entry = entry.unwrap();
         let path = entry.path().unwrap().display().to_string();
+        let sanitized_path = sanitize_relative_path(&path)?;
+        counters.account_entry(limits)?;
 
         match entry.header().entry_type() {
             tar::EntryType::Directory => {
+                if !match_list.matches(&path) {
+                    info!("Skipping directory {} (excluded)", &path);
+                    continue;
+                }
                 info!("Creating directory {}", &path);
-                let path = Path::new(&unpack_to).join(path.clone());
-                std::fs::create_dir_all(&path).unwrap();
+                let full_path = Path::new(&unpack_to).join(&sanitized_path);
+                std::fs::create_dir_all(&full_path).unwrap();
                 continue;
             }
             tar::EntryType::Symlink => {
-                let target_path = entry.link_name().unwrap().unwrap().display().to_string();
-                let path = Path::new(&unpack_to).join(path.clone());
-                let target_path = Path::new(&unpack_to).join(target_path);
-                info!("Creating symlink {:?} -> {:?}", &path, &target_path);
-                std::os::unix::fs::symlink(&target_path, &path).unwrap();
+                if !match_list.matches(&path) {
+                    info!("Skipping symlink {} (excluded)", &path);
+                    continue;
+                }
+                let raw_target = entry.link_name().unwrap().unwrap().display().to_string();
+                let full_path = Path::new(&unpack_to).join(&sanitized_path);
+                let target_path =
+                    resolve_link_target(&unpack_to_canon, &sanitized_path, &raw_target, &path)?;
+                info!("Creating symlink {:?} -> {:?}", &full_path, &target_path);
+                std::os::unix::fs::symlink(&target_path, &full_path).unwrap();
                 continue;
             }
             tar::EntryType::Link => {
-                let target_path = entry.link_name().unwrap().unwrap().display().to_string();
-                let path = Path::new(&unpack_to).join(path.clone());
-                let target_path = Path::new(&unpack_to).join(target_path);
+                if !match_list.matches(&path) {
+                    info!("Skipping hard link {} (excluded)", &path);
+                    continue;
+                }
+                let raw_target = entry.link_name().unwrap().unwrap().display().to_string();
+                let full_path = Path::new(&unpack_to).join(&sanitized_path);
+                let target_path =
+                    resolve_link_target(&unpack_to_canon, &sanitized_path, &raw_target, &path)?;
                 // the target_path should already exist in the same archive.
-                assert!(target_path.is_file());
-                info!("Creating hard link {:?} -> {:?}", &path, &target_path);
-                std::fs::hard_link(&target_path, &path).unwrap();
+                if !target_path.is_file() {
+                    return Err(UnpackError::HardLinkTargetMissing {
+                        path: path.clone(),
+                        target: raw_target,
+                    });
+                }
+                info!("Creating hard link {:?} -> {:?}", &full_path, &target_path);
+                std::fs::hard_link(&target_path, &full_path).unwrap();
                 continue;
             }
             _ => {}
@@ -357,19 +1050,41 @@ fn unpack_one_tar(path: std::path::PathBuf, unpack_to: String, fallocate_lock: A
             let split_metadata: SplitMetadata = serde_json::from_str(&buf).unwrap();
 
             assert!(maybe_split_metadata.is_none());
+
+            if !match_list.matches(&split_metadata.path) {
+                // Remember the metadata so the paired chunk-data entry right
+                // after this one is recognized and discarded too, instead of
+                // being mistaken for a fresh standalone file - without this,
+                // an excluded large file would still get partially written.
+                info!(
+                    "Skipping split-metadata for {} (excluded)",
+                    &split_metadata.path
+                );
+                maybe_split_metadata = Some(split_metadata);
+                continue;
+            }
+
+            let sanitized_target_path = sanitize_relative_path(&split_metadata.path)?;
+            let target_full_path = Path::new(&unpack_to).join(&sanitized_target_path);
+            if split_metadata.start_offset == 0 {
+                // One split-metadata entry is written per chunk of a large
+                // file, each carrying the same `total_size` - only count it
+                // once per logical file, on the first chunk, or the apparent
+                // size counter would be inflated by the chunk count.
+                counters.account_size(limits, split_metadata.total_size, 0)?;
+            }
             maybe_split_metadata = Some(split_metadata.clone());
 
-            let path = Path::new(&unpack_to).join(split_metadata.path);
-            ensure_parent_dir_exists(&path);
+            ensure_parent_dir_exists(&target_full_path);
 
             {
                 let _lock = fallocate_lock.lock().unwrap();
-                if !path.is_file() {
+                if !target_full_path.is_file() {
                     let f = OpenOptions::new()
                         .read(true)
                         .write(true)
                         .create(true)
-                        .open(&path)
+                        .open(&target_full_path)
                         .unwrap();
                     vmm_sys_util::fallocate::fallocate(
                         &f,
@@ -379,12 +1094,13 @@ fn unpack_one_tar(path: std::path::PathBuf, unpack_to: String, fallocate_lock: A
                         split_metadata.total_size as u64,
                     )
                     .unwrap();
-                    info!("Fallocated file {:?}", &path);
+                    counters.account_size(limits, 0, split_metadata.total_size)?;
+                    info!("Fallocated file {:?}", &target_full_path);
                 } else {
-                    let current_size = std::fs::metadata(&path).unwrap().len();
+                    let current_size = std::fs::metadata(&target_full_path).unwrap().len();
                     info!(
                         "File {:?} (size {}) already exists, skip falllocate",
-                        &path, current_size
+                        &target_full_path, current_size
                     );
                 }
             }
@@ -392,30 +1108,65 @@ fn unpack_one_tar(path: std::path::PathBuf, unpack_to: String, fallocate_lock: A
             continue;
         }
 
+        if path.contains("dedup-reference") {
+            let mut buf = String::new();
+            entry.read_to_string(&mut buf).unwrap();
+            let dedup_reference: DedupReference = serde_json::from_str(&buf).unwrap();
+
+            if !match_list.matches(&dedup_reference.path) {
+                info!(
+                    "Skipping dedup reference for {} (excluded)",
+                    &dedup_reference.path
+                );
+                continue;
+            }
+
+            // Validate now so a hostile/corrupt reference is rejected
+            // promptly; the real join happens in `apply_dedup_reference`,
+            // after every split's first pass.
+            sanitize_relative_path(&dedup_reference.path)?;
+            sanitize_relative_path(&dedup_reference.points_to)?;
+            counters.account_size(limits, dedup_reference.total_size, 0)?;
+            dedup_references.push(dedup_reference);
+
+            continue;
+        }
+
+        if !match_list.matches(&path) {
+            // Excluded: still drain the entry's bytes from the tar stream,
+            // but don't create or write anything. Any split-metadata fallocate
+            // for this path was already skipped above.
+            info!("Skipping {} (excluded)", &path);
+            std::io::copy(&mut entry, &mut std::io::sink()).ok();
+            maybe_split_metadata = None;
+            continue;
+        }
+
         info!("Handling regular file {:?}", &path);
 
-        let path = Path::new(&unpack_to).join(path);
+        let full_path = Path::new(&unpack_to).join(&sanitized_path);
         // create directory
-        ensure_parent_dir_exists(&path);
+        ensure_parent_dir_exists(&full_path);
         // create and write the file
         match maybe_split_metadata {
             Some(metadata) => {
-                assert!(path.is_file());
+                assert!(full_path.is_file());
                 let mut file = OpenOptions::new()
                     .read(true)
                     .write(true)
-                    .open(&path)
+                    .open(&full_path)
                     .unwrap();
                 let offset = file
                     .seek(SeekFrom::Start(metadata.start_offset as u64))
                     .unwrap();
                 let num_bytes_written = std::io::copy(&mut entry, &mut file).unwrap();
+                counters.account_size(limits, 0, num_bytes_written)?;
                 let finished_offset = file.seek(SeekFrom::Current(0)).unwrap();
                 info!(
                     "Writing {:?} (size {}) to {:?} (offset {}, physical_offset {}), {} written, finished_offset {}",
-                    &path,
+                    &full_path,
                     &entry.header().size().unwrap(),
-                    &path,
+                    &full_path,
                     &metadata.start_offset,
                     &offset,
                     &num_bytes_written,
@@ -424,36 +1175,192 @@ fn unpack_one_tar(path: std::path::PathBuf, unpack_to: String, fallocate_lock: A
                 maybe_split_metadata = None;
             }
             None => {
-                let mut file = File::create(&path).unwrap();
+                let mut file = File::create(&full_path).unwrap();
                 info!(
                     "Writing {:?} (size {}) to {:?}",
-                    &path,
+                    &full_path,
                     &entry.header().size().unwrap(),
-                    &path
+                    &full_path
                 );
-                std::io::copy(&mut entry, &mut file).unwrap();
+                let num_bytes_written = std::io::copy(&mut entry, &mut file).unwrap();
+                counters.account_size(limits, num_bytes_written, num_bytes_written)?;
             }
         };
     }
+    // `tar::Archive` stops reading once it sees the end-of-archive marker, so
+    // the hashing readers underneath won't have seen the trailing zero blocks
+    // yet. Drain them before checking for a mismatch so the hash covers the
+    // whole split, the same span the packer hashed.
+    let mut trailing = tar.into_inner();
+    std::io::copy(&mut trailing, &mut std::io::sink()).ok();
+    drop(trailing);
+    if let Some(msg) = mismatch.lock().unwrap().take() {
+        return Err(UnpackError::HashMismatch(msg));
+    }
+    Ok(dedup_references)
+}
+
+/// Links (or copies, if hard_link isn't possible) a dedup reference's path
+/// onto its original, which by this point must already be extracted -
+/// `unpack_split_tars` only calls this after every split has finished its
+/// first pass.
+fn apply_dedup_reference(unpack_to: &str, reference: &DedupReference) -> Result<(), UnpackError> {
+    let sanitized_path = sanitize_relative_path(&reference.path)?;
+    let sanitized_target = sanitize_relative_path(&reference.points_to)?;
+    let full_path = Path::new(unpack_to).join(&sanitized_path);
+    let target_full_path = Path::new(unpack_to).join(&sanitized_target);
+
+    if !target_full_path.is_file() {
+        return Err(UnpackError::DedupTargetMissing {
+            path: reference.path.clone(),
+            target: reference.points_to.clone(),
+        });
+    }
+
+    ensure_parent_dir_exists(&full_path);
+    if std::fs::hard_link(&target_full_path, &full_path).is_err() {
+        std::fs::copy(&target_full_path, &full_path).map_err(|_| {
+            UnpackError::DedupTargetMissing {
+                path: reference.path.clone(),
+                target: reference.points_to.clone(),
+            }
+        })?;
+    }
+    info!(
+        "Linked {:?} -> {:?} (dedup reference)",
+        &full_path, &target_full_path
+    );
+    Ok(())
+}
+
+/// Scans every split for dedup-reference entries and returns the set of
+/// `points_to` targets that at least one surviving (not filtered out)
+/// reference depends on. A lenient pre-scan: any entry/split that can't be
+/// read is just skipped here, since the real extraction pass handles
+/// reporting those errors properly.
+fn collect_forced_dedup_targets(tar_files: &[PathBuf], match_list: &MatchList) -> HashSet<String> {
+    let mut forced = HashSet::new();
+    for path in tar_files {
+        let Ok(raw_file) = File::open(path) else {
+            continue;
+        };
+        let Ok(reader) = wrap_decoder(Box::new(raw_file)) else {
+            continue;
+        };
+        let mut tar = tar::Archive::new(reader);
+        let Ok(entries) = tar.entries() else {
+            continue;
+        };
+        for entry in entries {
+            let Ok(mut entry) = entry else {
+                continue;
+            };
+            let Ok(entry_path) = entry.path().map(|p| p.display().to_string()) else {
+                continue;
+            };
+            if !entry_path.contains("dedup-reference") {
+                continue;
+            }
+            let mut buf = String::new();
+            if entry.read_to_string(&mut buf).is_err() {
+                continue;
+            }
+            let Ok(dedup_reference) = serde_json::from_str::<DedupReference>(&buf) else {
+                continue;
+            };
+            if match_list.matches(&dedup_reference.path) {
+                forced.insert(dedup_reference.points_to);
+            }
+        }
+    }
+    forced
 }
 
 use rayon::prelude::*;
 
-fn unpack_split_tars(location: String, unpack_to: String) {
+fn unpack_split_tars(
+    location: String,
+    unpack_to: String,
+    limits: UnpackLimits,
+    fail_fast: bool,
+    match_list: MatchList,
+) {
     let mut tar_files = Path::new(&location)
         .read_dir()
         .unwrap()
         .map(|entry| entry.unwrap().path())
-        .filter(|path| path.is_file())
+        .filter(|path| {
+            path.is_file() && path.extension().and_then(|ext| ext.to_str()) != Some("sha256")
+        })
         .collect::<Vec<_>>();
     tar_files.sort();
 
+    // A dedup reference's original may have been excluded by --include/
+    // --exclude even though the duplicate depending on it wasn't; pull such
+    // originals back in so the reference can still resolve instead of
+    // aborting the whole run. Skip the extra scan entirely when there are no
+    // rules: every path then gets the same default answer, so no kept
+    // reference can depend on an excluded original.
+    let match_list = if match_list.rules.is_empty() {
+        match_list
+    } else {
+        let forced_targets = collect_forced_dedup_targets(&tar_files, &match_list);
+        if !forced_targets.is_empty() {
+            info!(
+                "Forcing extraction of {} dedup target(s) excluded by --include/--exclude but still referenced",
+                forced_targets.len()
+            );
+        }
+        match_list.with_forced_includes(&forced_targets)
+    };
+
     let fallocate_lock = Arc::new(Mutex::new(()));
+    let counters = UnpackCounters::default();
 
     // multi-thread this later
-    tar_files.into_par_iter().for_each(|path| {
-        unpack_one_tar(path.clone(), unpack_to.clone(), fallocate_lock.clone());
-    });
+    // First pass: extract every split's regular files/dirs/links in
+    // parallel, deferring dedup references (which may point at an original
+    // in a different split) instead of linking them immediately.
+    let result: Result<Vec<Vec<DedupReference>>, UnpackError> = tar_files
+        .into_par_iter()
+        .map(|path| {
+            match unpack_one_tar(
+                path.clone(),
+                unpack_to.clone(),
+                fallocate_lock.clone(),
+                &limits,
+                &counters,
+                &match_list,
+            ) {
+                Ok(dedup_references) => Ok(dedup_references),
+                // Integrity failures are the only error class that a non-fail-fast
+                // run tolerates: report the corrupt split (its entries are
+                // already written), drop its dedup references and keep going.
+                Err(e @ UnpackError::HashMismatch(_)) if !fail_fast => {
+                    eprintln!("Skipping {:?} due to integrity failure: {}", path, e);
+                    Ok(Vec::new())
+                }
+                Err(e) => Err(e),
+            }
+        })
+        .collect();
+
+    let dedup_references = match result {
+        Ok(per_split) => per_split.into_iter().flatten().collect::<Vec<_>>(),
+        Err(e) => {
+            eprintln!("Aborting unpack: {}", e);
+            std::process::exit(1);
+        }
+    };
+
+    // Second pass: every split's originals are now on disk, so it's safe to
+    // link the deferred dedup references.
+    for reference in &dedup_references {
+        if let Err(e) = apply_dedup_reference(&unpack_to, reference) {
+            eprintln!("Aborting unpack: {}", e);
+            std::process::exit(1);
+        }
+    }
 }
 
 fn main() {
@@ -461,10 +1368,26 @@ fn main() {
         .filter_level(log::LevelFilter::Info)
         .init();
 
-    let args = Cli::parse();
+    // Parsed via `ArgMatches` (rather than plain `Cli::parse()`) so we can
+    // recover the relative order `--include`/`--exclude` were given in, which
+    // `MatchList::new` needs for its "last match wins" semantics.
+    let matches = Cli::command().get_matches();
+    let args = Cli::from_arg_matches(&matches).unwrap();
 
     if args.unpack_from.is_some() {
-        unpack_split_tars(args.unpack_from.unwrap(), args.unpack_to.unwrap());
+        let limits = UnpackLimits {
+            max_apparent_size: args.max_apparent_size,
+            max_disk_size: args.max_disk_size,
+            max_entry_count: args.max_entry_count,
+        };
+        let match_list = MatchList::new(&matches, args.match_default_include);
+        unpack_split_tars(
+            args.unpack_from.unwrap(),
+            args.unpack_to.unwrap(),
+            limits,
+            args.fail_fast,
+            match_list,
+        );
         return;
     }
 
@@ -478,25 +1401,20 @@ fn main() {
             let parsed_size = parse_size::parse_size(args.split_size.as_ref().unwrap()).unwrap();
             info!("Splitting into files of size {:?}", parsed_size);
 
+            let match_list = MatchList::new(&matches, args.match_default_include);
             Some(WriterState::new(
                 parsed_size,
                 split_to,
                 args.compression.clone(),
+                args.zstd_level,
                 args.tar_source_from,
+                match_list,
             ))
         }
         None => None,
     };
-    // let decoder: Box<dyn Read> = match args.compression.as_str() {
-    //     "zstd" => Box::new(zstd::stream::read::Decoder::new(std::io::stdin()).unwrap()),
-    //     "gzip" => Box::new(flate2::bufread::GzDecoder::new(std::io::BufReader::new(
-    //         std::io::stdin(),
-    //     ))),
-    //     "none" => Box::new(std::io::stdin()),
-    //     _ => {
-    //         panic!("Unknown compression type, must be either zstd or gzip");
-    //     }
-    // };
+    // The input read from stdin is always a plain (uncompressed) tar stream;
+    // `--compression` only selects how the *output* splits are encoded.
     let decoder: Box<dyn Read> = Box::new(std::io::stdin());
     let mut tar = tar::Archive::new(decoder);
 